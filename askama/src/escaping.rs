@@ -0,0 +1,117 @@
+use std::fmt::{self, Write};
+
+/// Decides how the string value of a `Node::Expr` is written to the output.
+///
+/// Each target context (HTML body, a JSON/JS string literal, a URL query
+/// component, or raw output) needs different characters escaped. An
+/// implementation writes `value` to `out`, substituting the characters that
+/// would otherwise be interpreted by the surrounding context.
+pub trait Escaper {
+    fn write_escaped<W: Write>(&self, out: &mut W, value: &str) -> fmt::Result;
+}
+
+/// Escapes the five characters that are significant in HTML text and
+/// double-quoted attribute values. This is the default escaper.
+pub struct Html;
+
+impl Escaper for Html {
+    fn write_escaped<W: Write>(&self, out: &mut W, value: &str) -> fmt::Result {
+        for c in value.chars() {
+            match c {
+                '&' => out.write_str("&amp;")?,
+                '<' => out.write_str("&lt;")?,
+                '>' => out.write_str("&gt;")?,
+                '"' => out.write_str("&quot;")?,
+                '\'' => out.write_str("&#x27;")?,
+                _ => out.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Escapes a value for inclusion in a JSON or JavaScript string literal.
+pub struct Json;
+
+impl Escaper for Json {
+    fn write_escaped<W: Write>(&self, out: &mut W, value: &str) -> fmt::Result {
+        for c in value.chars() {
+            match c {
+                '"' => out.write_str("\\\"")?,
+                '\\' => out.write_str("\\\\")?,
+                '\n' => out.write_str("\\n")?,
+                '\r' => out.write_str("\\r")?,
+                '\t' => out.write_str("\\t")?,
+                '<' => out.write_str("\\u003c")?,
+                '>' => out.write_str("\\u003e")?,
+                '&' => out.write_str("\\u0026")?,
+                c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+                _ => out.write_char(c)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Percent-encodes everything outside the unreserved URL character set.
+pub struct Url;
+
+impl Escaper for Url {
+    fn write_escaped<W: Write>(&self, out: &mut W, value: &str) -> fmt::Result {
+        for b in value.bytes() {
+            match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9'
+                    | b'-' | b'_' | b'.' | b'~' => out.write_char(b as char)?,
+                _ => write!(out, "%{:02X}", b)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes the value through unchanged, applying no escaping.
+pub struct NoEscape;
+
+impl Escaper for NoEscape {
+    fn write_escaped<W: Write>(&self, out: &mut W, value: &str) -> fmt::Result {
+        out.write_str(value)
+    }
+}
+
+/// The built-in filter name that marks its operand as already safe for the
+/// active output context. A `Node::Expr` whose expression is wrapped in a
+/// `Filter` carrying this name bypasses the `Escaper` instead of being
+/// escaped again, which is how authors emit raw markup deliberately.
+pub const SAFE_FILTER: &'static str = "safe";
+
+/// The escaper a template targets, selected by the `escape` configuration
+/// attribute. `safe` subexpressions bypass whichever escaper is active.
+pub enum Escaping {
+    Html,
+    Json,
+    Url,
+    None,
+}
+
+impl Escaping {
+    /// Resolves the escaper named in a crate/derive attribute, defaulting to
+    /// HTML escaping when no name is given.
+    pub fn from_name(name: &str) -> Escaping {
+        match name {
+            "" | "html" => Escaping::Html,
+            "json" | "js" => Escaping::Json,
+            "url" => Escaping::Url,
+            "none" => Escaping::None,
+            _ => panic!("unknown escaper: {}", name),
+        }
+    }
+
+    pub fn write_escaped<W: Write>(&self, out: &mut W, value: &str) -> fmt::Result {
+        match *self {
+            Escaping::Html => Html.write_escaped(out, value),
+            Escaping::Json => Json.write_escaped(out, value),
+            Escaping::Url => Url.write_escaped(out, value),
+            Escaping::None => NoEscape.write_escaped(out, value),
+        }
+    }
+}
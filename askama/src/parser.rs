@@ -1,11 +1,30 @@
+use escaping::SAFE_FILTER;
+use memchr::memchr;
 use nom::{self, IResult};
 use std::str;
 
 pub enum Expr<'a> {
     StrLit(&'a str),
     Var(&'a str),
-    Filter(&'a str, Box<Expr<'a>>),
-    Compare(&'a str, Box<Expr<'a>>, Box<Expr<'a>>),
+    Attr(Box<Expr<'a>>, &'a str),
+    Index(Box<Expr<'a>>, Box<Expr<'a>>),
+    MethodCall(Box<Expr<'a>>, &'a str, Vec<Expr<'a>>),
+    Filter(&'a str, Box<Expr<'a>>, Vec<Expr<'a>>),
+    Unary(&'a str, Box<Expr<'a>>),
+    BinOp(&'a str, Box<Expr<'a>>, Box<Expr<'a>>),
+}
+
+impl<'a> Expr<'a> {
+    /// Whether this expression is wrapped in the built-in `safe` filter,
+    /// meaning its value is already escaped for the active output context and
+    /// must bypass the `Escaper`. The codegen side checks this to decide
+    /// whether a `Node::Expr` is routed through the active escaper.
+    pub fn is_marked_safe(&self) -> bool {
+        match *self {
+            Expr::Filter(name, _, _) => name == SAFE_FILTER,
+            _ => false,
+        }
+    }
 }
 
 pub enum Target<'a> {
@@ -23,6 +42,10 @@ pub enum Node<'a> {
     Extends(Expr<'a>),
     BlockDef(WS, &'a str, Vec<Node<'a>>, WS),
     Block(WS, &'a str, WS),
+    Include(WS, Expr<'a>),
+    Macro(WS, &'a str, Vec<Target<'a>>, Vec<Node<'a>>, WS),
+    Call(WS, &'a str, Vec<Expr<'a>>),
+    Set(WS, Target<'a>, Expr<'a>),
 }
 
 pub type Cond<'a> = (WS, Option<Expr<'a>>, Vec<Node<'a>>);
@@ -57,14 +80,15 @@ fn take_content(i: &[u8]) -> IResult<&[u8], Node> {
     if i.len() < 1 || i[0] == b'{' {
         return IResult::Error(error_position!(nom::ErrorKind::TakeUntil, i));
     }
-    for (j, c) in i.iter().enumerate() {
-        if *c == b'{' {
-            if i.len() < j + 2 {
-                return IResult::Done(&i[..0], split_ws_parts(&i[..]));
-            } else if i[j + 1] == b'{' || i[j + 1] == b'%' {
-                return IResult::Done(&i[j..], split_ws_parts(&i[..j]));
-            }
+    let mut start = 0;
+    while let Some(off) = memchr(b'{', &i[start..]) {
+        let j = start + off;
+        if i.len() < j + 2 {
+            break;
+        } else if i[j + 1] == b'{' || i[j + 1] == b'%' {
+            return IResult::Done(&i[j..], split_ws_parts(&i[..j]));
         }
+        start = j + 1;
     }
     IResult::Done(&i[..0], split_ws_parts(&i[..]))
 }
@@ -74,10 +98,65 @@ named!(expr_str_lit<Expr>, map!(
     |s| Expr::StrLit(str::from_utf8(s).unwrap())
 ));
 
-named!(expr_var<Expr>, map!(nom::alphanumeric,
-    |s| Expr::Var(str::from_utf8(s).unwrap())
+named!(expr_args<Vec<Expr>>, delimited!(
+    char!('('),
+    separated_list!(char!(','), ws!(expr_any)),
+    char!(')')
+));
+
+named!(expr_index<Expr>, delimited!(
+    char!('['), ws!(expr_any), char!(']')
 ));
 
+fn expr_var(i: &[u8]) -> IResult<&[u8], Expr> {
+    let (mut left, mut expr) = match nom::alphanumeric(i) {
+        IResult::Error(err) => { return IResult::Error(err); },
+        IResult::Incomplete(needed) => { return IResult::Incomplete(needed); },
+        IResult::Done(left, res) => (left, Expr::Var(str::from_utf8(res).unwrap())),
+    };
+    loop {
+        if left.is_empty() {
+            break;
+        }
+        match left[0] {
+            b'.' => {
+                let name = match nom::alphanumeric(&left[1..]) {
+                    IResult::Error(err) => { return IResult::Error(err); },
+                    IResult::Incomplete(needed) => { return IResult::Incomplete(needed); },
+                    IResult::Done(new_left, res) => {
+                        left = new_left;
+                        str::from_utf8(res).unwrap()
+                    },
+                };
+                if !left.is_empty() && left[0] == b'(' {
+                    match expr_args(left) {
+                        IResult::Error(err) => { return IResult::Error(err); },
+                        IResult::Incomplete(needed) => { return IResult::Incomplete(needed); },
+                        IResult::Done(new_left, args) => {
+                            left = new_left;
+                            expr = Expr::MethodCall(Box::new(expr), name, args);
+                        },
+                    };
+                } else {
+                    expr = Expr::Attr(Box::new(expr), name);
+                }
+            },
+            b'[' => {
+                match expr_index(left) {
+                    IResult::Error(err) => { return IResult::Error(err); },
+                    IResult::Incomplete(needed) => { return IResult::Incomplete(needed); },
+                    IResult::Done(new_left, index) => {
+                        left = new_left;
+                        expr = Expr::Index(Box::new(expr), Box::new(index));
+                    },
+                };
+            },
+            _ => { break; },
+        }
+    }
+    IResult::Done(left, expr)
+}
+
 named!(target_single<Target>, map!(nom::alphanumeric,
     |s| Target::Name(str::from_utf8(s).unwrap())
 ));
@@ -88,7 +167,7 @@ fn expr_filtered(i: &[u8]) -> IResult<&[u8], Expr> {
         IResult::Incomplete(needed) => { return IResult::Incomplete(needed); },
         IResult::Done(left, res) => (left, res),
     };
-    while left[0] == b'|' {
+    while !left.is_empty() && left[0] == b'|' {
         match nom::alphanumeric(&left[1..]) {
             IResult::Error(err) => {
                 return IResult::Error(err);
@@ -98,30 +177,146 @@ fn expr_filtered(i: &[u8]) -> IResult<&[u8], Expr> {
             },
             IResult::Done(new_left, res) => {
                 left = new_left;
-                expr = Expr::Filter(str::from_utf8(res).unwrap(), Box::new(expr));
+                let name = str::from_utf8(res).unwrap();
+                let args = if !left.is_empty() && left[0] == b'(' {
+                    match expr_args(left) {
+                        IResult::Error(err) => { return IResult::Error(err); },
+                        IResult::Incomplete(needed) => { return IResult::Incomplete(needed); },
+                        IResult::Done(new_left, args) => { left = new_left; args },
+                    }
+                } else {
+                    Vec::new()
+                };
+                expr = Expr::Filter(name, Box::new(expr), args);
             },
         };
     }
     IResult::Done(left, expr)
 }
 
-named!(expr_compare<Expr>, do_parse!(
-    left: expr_filtered >>
-    op: ws!(alt!(
-        tag_s!("==") | tag_s!("!=") |
-        tag_s!(">=") | tag_s!(">") |
-        tag_s!("<=") | tag_s!("<")
-    )) >>
-    right: expr_filtered >>
-    (Expr::Compare(str::from_utf8(op).unwrap(),
-                   Box::new(left), Box::new(right)))
-));
+fn skip_ws(i: &[u8]) -> &[u8] {
+    let mut i = i;
+    while !i.is_empty() && (i[0] == b' ' || i[0] == b'\t' || i[0] == b'\r' || i[0] == b'\n') {
+        i = &i[1..];
+    }
+    i
+}
 
-named!(expr_any<Expr>, alt!(
-    expr_compare |
-    expr_filtered |
-    expr_str_lit
-));
+fn is_ident_byte(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+fn starts_with_word(i: &[u8], word: &str) -> bool {
+    let w = word.as_bytes();
+    i.starts_with(w) && (i.len() == w.len() || !is_ident_byte(i[w.len()]))
+}
+
+// Binding power shared by the comparison operators; `not` binds its operand at
+// this level too, so `not a == b` groups as `not (a == b)`.
+const COMPARE_BP: u8 = 3;
+// Unary `-` binds tighter than every binary operator in the `OPS` table.
+const PREFIX_BP: u8 = 6;
+
+// `%` and `-` double as the first byte of the `%}`/`-%}`/`-}}` delimiters
+// that close a `{% ... %}`/`{{ ... }}` tag, so the operator loop must not
+// mistake a terminator for the start of an operator.
+fn starts_with_terminator(i: &[u8]) -> bool {
+    i.starts_with(b"%}") || i.starts_with(b"-%}") || i.starts_with(b"-}}")
+}
+
+// Binding powers: higher binds tighter. Word operators require a word
+// boundary so that `and`/`or`/`not` are not confused with identifiers.
+fn binary_op(i: &[u8]) -> Option<(&'static str, u8)> {
+    const OPS: &[(&str, u8)] = &[
+        ("or", 1),
+        ("and", 2),
+        ("==", COMPARE_BP), ("!=", COMPARE_BP),
+        ("<=", COMPARE_BP), (">=", COMPARE_BP),
+        ("<", COMPARE_BP), (">", COMPARE_BP),
+        ("+", 4), ("-", 4),
+        ("*", 5), ("/", 5), ("%", 5),
+    ];
+    if starts_with_terminator(i) {
+        return None;
+    }
+    for &(op, bp) in OPS {
+        let alpha = op.as_bytes()[0].is_ascii_alphabetic();
+        let matched = if alpha { starts_with_word(i, op) } else { i.starts_with(op.as_bytes()) };
+        if matched {
+            return Some((op, bp));
+        }
+    }
+    None
+}
+
+// Precedence-climbing (Pratt) expression parser. Parses a prefix/unary term,
+// then folds following binary operators whose left binding power is at least
+// `min_bp` into `BinOp` nodes, recursing at `left_bp + 1` for left
+// associativity.
+fn parse_expr(i: &[u8], min_bp: u8) -> IResult<&[u8], Expr> {
+    let (mut rest, mut lhs) = match parse_prefix(i) {
+        IResult::Error(err) => { return IResult::Error(err); },
+        IResult::Incomplete(needed) => { return IResult::Incomplete(needed); },
+        IResult::Done(rest, res) => (rest, res),
+    };
+    loop {
+        let after_ws = skip_ws(rest);
+        let (op, left_bp) = match binary_op(after_ws) {
+            Some(found) => found,
+            None => { break; },
+        };
+        if left_bp < min_bp {
+            break;
+        }
+        let rhs_input = &after_ws[op.len()..];
+        match parse_expr(rhs_input, left_bp + 1) {
+            IResult::Error(err) => { return IResult::Error(err); },
+            IResult::Incomplete(needed) => { return IResult::Incomplete(needed); },
+            IResult::Done(new_rest, rhs) => {
+                rest = new_rest;
+                lhs = Expr::BinOp(op, Box::new(lhs), Box::new(rhs));
+            },
+        };
+    }
+    IResult::Done(rest, lhs)
+}
+
+fn parse_prefix(i: &[u8]) -> IResult<&[u8], Expr> {
+    let i = skip_ws(i);
+    if starts_with_word(i, "not") {
+        return match parse_expr(&i[3..], COMPARE_BP) {
+            IResult::Error(err) => IResult::Error(err),
+            IResult::Incomplete(needed) => IResult::Incomplete(needed),
+            IResult::Done(rest, res) => IResult::Done(rest, Expr::Unary("not", Box::new(res))),
+        };
+    }
+    if !i.is_empty() && i[0] == b'-' {
+        return match parse_expr(&i[1..], PREFIX_BP) {
+            IResult::Error(err) => IResult::Error(err),
+            IResult::Incomplete(needed) => IResult::Incomplete(needed),
+            IResult::Done(rest, res) => IResult::Done(rest, Expr::Unary("-", Box::new(res))),
+        };
+    }
+    if !i.is_empty() && i[0] == b'(' {
+        return match parse_expr(&i[1..], 0) {
+            IResult::Error(err) => IResult::Error(err),
+            IResult::Incomplete(needed) => IResult::Incomplete(needed),
+            IResult::Done(rest, res) => {
+                let rest = skip_ws(rest);
+                if !rest.is_empty() && rest[0] == b')' {
+                    IResult::Done(&rest[1..], res)
+                } else {
+                    IResult::Error(error_position!(nom::ErrorKind::Char, rest))
+                }
+            },
+        };
+    }
+    alt!(i, expr_filtered | expr_str_lit)
+}
+
+fn expr_any(i: &[u8]) -> IResult<&[u8], Expr> {
+    parse_expr(i, 0)
+}
 
 named!(expr_node<Node>, do_parse!(
     tag_s!("{{") >>
@@ -170,6 +365,13 @@ named!(block_if<Node>, do_parse!(
     })
 ));
 
+// Inside a loop body the reserved name `loop` exposes iteration state
+// (`loop.index`, `loop.index0`, `loop.first`, `loop.last`). This needs no
+// dedicated grammar: it parses as ordinary attribute access (`Expr::Attr` on
+// `Expr::Var("loop")`), see `test_loop_metadata_parses`. Binding that name to
+// a counter and, for `loop.last`, peeking the materialized iterator, is a
+// codegen concern that has no implementation in this crate yet; only the
+// parse side described above exists today.
 named!(block_for<Node>, do_parse!(
     tag_s!("{%") >>
     pws1: opt!(tag_s!("-")) >>
@@ -216,13 +418,76 @@ named!(block_block<Node>, do_parse!(
                     WS(pws2.is_some(), pws2.is_some())))
 ));
 
+named!(macro_args<Vec<Target>>, delimited!(
+    char!('('),
+    separated_list!(char!(','), ws!(target_single)),
+    char!(')')
+));
+
+named!(block_include<Node>, do_parse!(
+    tag_s!("{%") >>
+    pws: opt!(tag_s!("-")) >>
+    ws!(tag_s!("include")) >>
+    name: ws!(expr_str_lit) >>
+    nws: opt!(tag_s!("-")) >>
+    tag_s!("%}") >>
+    (Node::Include(WS(pws.is_some(), nws.is_some()), name))
+));
+
+named!(block_macro<Node>, do_parse!(
+    tag_s!("{%") >>
+    pws1: opt!(tag_s!("-")) >>
+    ws!(tag_s!("macro")) >>
+    name: ws!(nom::alphanumeric) >>
+    args: ws!(macro_args) >>
+    nws1: opt!(tag_s!("-")) >>
+    tag_s!("%}") >>
+    contents: parse_template >>
+    tag_s!("{%") >>
+    pws2: opt!(tag_s!("-")) >>
+    ws!(tag_s!("endmacro")) >>
+    nws2: opt!(tag_s!("-")) >>
+    tag_s!("%}") >>
+    (Node::Macro(WS(pws1.is_some(), nws1.is_some()),
+                 str::from_utf8(name).unwrap(), args, contents,
+                 WS(pws2.is_some(), nws2.is_some())))
+));
+
+named!(block_call<Node>, do_parse!(
+    tag_s!("{%") >>
+    pws: opt!(tag_s!("-")) >>
+    ws!(tag_s!("call")) >>
+    name: ws!(nom::alphanumeric) >>
+    args: ws!(expr_args) >>
+    nws: opt!(tag_s!("-")) >>
+    tag_s!("%}") >>
+    (Node::Call(WS(pws.is_some(), nws.is_some()),
+                str::from_utf8(name).unwrap(), args))
+));
+
+named!(block_set<Node>, do_parse!(
+    tag_s!("{%") >>
+    pws: opt!(tag_s!("-")) >>
+    ws!(tag_s!("set")) >>
+    var: ws!(target_single) >>
+    ws!(tag_s!("=")) >>
+    val: ws!(expr_any) >>
+    nws: opt!(tag_s!("-")) >>
+    tag_s!("%}") >>
+    (Node::Set(WS(pws.is_some(), nws.is_some()), var, val))
+));
+
 named!(parse_template<Vec<Node<'a>>>, many0!(alt!(
     take_content |
     expr_node |
     block_if |
     block_for |
     block_extends |
-    block_block
+    block_block |
+    block_include |
+    block_macro |
+    block_call |
+    block_set
 )));
 
 pub fn parse(src: &str) -> Vec<Node> {
@@ -254,4 +519,117 @@ mod tests {
         check_ws_split("b\n", &("", "b", "\n"));
         check_ws_split(" \t\r\n", &(" \t\r\n", "", ""));
     }
+
+    fn args_str(args: &[super::Expr]) -> String {
+        args.iter().map(expr_str).collect::<Vec<_>>().join(", ")
+    }
+
+    // Renders a parsed expression into a normalized, fully-parenthesized form
+    // so that operator precedence and associativity can be asserted exactly.
+    fn expr_str(e: &super::Expr) -> String {
+        use super::Expr::*;
+        match *e {
+            StrLit(s) => format!("{:?}", s),
+            Var(s) => s.to_string(),
+            Attr(ref obj, name) => format!("{}.{}", expr_str(obj), name),
+            Index(ref obj, ref idx) => format!("{}[{}]", expr_str(obj), expr_str(idx)),
+            MethodCall(ref obj, name, ref args) =>
+                format!("{}.{}({})", expr_str(obj), name, args_str(args)),
+            Filter(name, ref obj, ref args) => if args.is_empty() {
+                format!("{}|{}", expr_str(obj), name)
+            } else {
+                format!("{}|{}({})", expr_str(obj), name, args_str(args))
+            },
+            Unary(op, ref inner) => format!("({} {})", op, expr_str(inner)),
+            BinOp(op, ref lhs, ref rhs) =>
+                format!("({} {} {})", expr_str(lhs), op, expr_str(rhs)),
+        }
+    }
+
+    fn parse_expr(s: &str) -> String {
+        match super::expr_any(s.as_bytes()) {
+            super::IResult::Done(left, expr) => {
+                assert!(left.is_empty(), "unparsed input remaining");
+                expr_str(&expr)
+            },
+            _ => panic!("failed to parse expression: {:?}", s),
+        }
+    }
+
+    #[test]
+    fn test_expr_postfix() {
+        assert_eq!(parse_expr("user.name"), "user.name");
+        assert_eq!(parse_expr("items[0]"), "items[0]");
+        assert_eq!(parse_expr("a.b[0].c()"), "a.b[0].c()");
+    }
+
+    #[test]
+    fn test_expr_precedence() {
+        assert_eq!(parse_expr("a + b * c"), "(a + (b * c))");
+        assert_eq!(parse_expr("not a and b"), "((not a) and b)");
+        assert_eq!(parse_expr("a < b or c|filter(1)"), "((a < b) or c|filter(1))");
+    }
+
+    // `%` and `-` also start the `%}`/`-%}`/`-}}` tag delimiters, so the
+    // operator loop must stop at them instead of trying to parse the
+    // delimiter as a binary operator.
+    #[test]
+    fn test_expr_stops_at_tag_terminators() {
+        match super::expr_any(b"a %}") {
+            super::IResult::Done(left, expr) => {
+                assert_eq!(expr_str(&expr), "a");
+                assert_eq!(left, b" %}");
+            },
+            _ => panic!("failed to parse expression"),
+        }
+        match super::expr_any(b"a -%}") {
+            super::IResult::Done(left, expr) => {
+                assert_eq!(expr_str(&expr), "a");
+                assert_eq!(left, b" -%}");
+            },
+            _ => panic!("failed to parse expression"),
+        }
+        match super::expr_any(b"a -}}") {
+            super::IResult::Done(left, expr) => {
+                assert_eq!(expr_str(&expr), "a");
+                assert_eq!(left, b" -}}");
+            },
+            _ => panic!("failed to parse expression"),
+        }
+    }
+
+    fn block_count(src: &str) -> usize {
+        super::parse(src).len()
+    }
+
+    #[test]
+    fn test_if_for_set_parse() {
+        assert_eq!(block_count("{% if a %}x{% endif %}"), 1);
+        assert_eq!(block_count("{% for x in items %}x{% endfor %}"), 1);
+        assert_eq!(block_count("{% set x = y %}"), 1);
+        assert_eq!(block_count("{% if a -%}x{%- endif %}"), 1);
+    }
+
+    #[test]
+    fn test_set_parse_contents() {
+        let mut nodes = super::parse("{% set x = 1 + y %}");
+        assert_eq!(nodes.len(), 1);
+        match nodes.pop().unwrap() {
+            super::Node::Set(_, super::Target::Name(name), val) => {
+                assert_eq!(name, "x");
+                assert_eq!(expr_str(&val), "(1 + y)");
+            },
+            _ => panic!("expected a Set node"),
+        }
+    }
+
+    // `loop.index`/`loop.first`/`loop.last` need no dedicated grammar: they
+    // parse as plain attribute access on `Expr::Var("loop")`.
+    #[test]
+    fn test_loop_metadata_parses() {
+        assert_eq!(parse_expr("loop.index"), "loop.index");
+        assert_eq!(parse_expr("loop.index0"), "loop.index0");
+        assert_eq!(parse_expr("loop.first"), "loop.first");
+        assert_eq!(parse_expr("loop.last"), "loop.last");
+    }
 }
\ No newline at end of file